@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+/// Startup configuration mapping room names to their shared secret. Rooms not
+/// present in the map are public and require no authentication, so the default
+/// (empty) configuration leaves every room open exactly as before.
+#[derive(Clone, Default)]
+pub struct AuthConfig {
+    secrets: HashMap<String, String>,
+}
+
+impl AuthConfig {
+    /// Load the configuration from a JSON file of `{ "room": "secret" }`
+    /// pairs. The path is taken from the `CHAT_AUTH_CONFIG` environment
+    /// variable, defaulting to `auth.json`. A missing or unreadable file
+    /// yields an empty configuration (all rooms public).
+    pub fn load() -> AuthConfig {
+        let path = std::env::var("CHAT_AUTH_CONFIG").unwrap_or_else(|_| "auth.json".to_string());
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<HashMap<String, String>>(&contents) {
+                Ok(secrets) => AuthConfig { secrets },
+                Err(e) => {
+                    eprintln!("could not parse auth config {}: {}", path, e);
+                    AuthConfig::default()
+                }
+            },
+            Err(_) => AuthConfig::default(),
+        }
+    }
+
+    /// Whether a room is private and therefore demands a token on join.
+    pub fn requires_auth(&self, room: &str) -> bool {
+        self.secrets.contains_key(room)
+    }
+
+    /// Check a supplied token against a room's secret. Public rooms accept any
+    /// token (including none).
+    pub fn verify(&self, room: &str, token: &str) -> bool {
+        match self.secrets.get(room) {
+            Some(secret) => secret == token,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    /// Testing module for the authentication config.
+    use super::*;
+
+    fn private(room: &str, secret: &str) -> AuthConfig {
+        let mut secrets = HashMap::new();
+        secrets.insert(room.to_string(), secret.to_string());
+        AuthConfig { secrets }
+    }
+
+    #[test]
+    fn public_room_requires_no_auth_and_accepts_any_token() {
+        let auth = AuthConfig::default();
+        assert!(!auth.requires_auth("lobby"));
+        assert!(auth.verify("lobby", "anything"));
+        assert!(auth.verify("lobby", ""));
+    }
+
+    #[test]
+    fn private_room_requires_matching_token() {
+        let auth = private("secret", "hunter2");
+        assert!(auth.requires_auth("secret"));
+        assert!(auth.verify("secret", "hunter2"));
+        assert!(!auth.verify("secret", "wrong"));
+        assert!(!auth.verify("secret", ""));
+    }
+
+    #[test]
+    fn unconfigured_room_stays_public() {
+        let auth = private("secret", "hunter2");
+        assert!(!auth.requires_auth("other"));
+        assert!(auth.verify("other", "whatever"));
+    }
+}
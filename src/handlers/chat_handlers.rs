@@ -3,32 +3,147 @@ use std::collections::HashMap;
 use tokio::sync::{mpsc, RwLock};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use futures_util::{SinkExt, StreamExt, TryFutureExt};
+use serde::Deserialize;
 use warp::ws::{Message, WebSocket};
 
+use tokio::sync::watch;
+
+use crate::auth::AuthConfig;
+use crate::logger::{Logger, LogRecord};
+use crate::metrics::Metrics;
+
 /// Our global unique user id counter.
 static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
 
+/// Default number of backlog lines replayed to a joining user when the
+/// `history` query parameter is omitted.
+const DEFAULT_HISTORY: usize = 0;
+
+/// Query string accepted on the chat path, e.g. `/chat/<room>?history=50`.
+#[derive(Debug, Deserialize)]
+pub struct ChatQuery {
+    /// How many recent lines to replay to the joining user.
+    pub history: Option<usize>,
+}
+
+/// The frame a client sends to authenticate into a private room.
+#[derive(Debug, Deserialize)]
+struct Authenticate {
+    token: String,
+}
+
+/// State of the pre-broadcast authentication handshake. A socket progresses
+/// `Unauthenticated -> Authenticated` before it is ever added to room state;
+/// public rooms start already `Authenticated`.
+enum AuthState {
+    Unauthenticated,
+    Authenticated,
+}
+
+/// The identity a client supplies in the registration handshake.
+///
+/// This mirrors the nickname/username/realname identity model used by
+/// IRC-style servers: the `nickname` is the short handle shown in chat,
+/// while `realname` carries a longer human-readable description.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegisteredUser {
+    pub nickname: String,
+    pub realname: String,
+}
+
+/// The outbound half of a connected user, abstracted over the transport so
+/// the same room can hold both WebSocket and IRC clients. A broadcast is
+/// rendered differently per protocol: a WebSocket client receives a text
+/// frame, while an IRC client receives a `:nick PRIVMSG #room :text` line.
+#[derive(Clone)]
+pub enum Sink {
+    /// A WebSocket client, fed `warp::ws::Message` frames.
+    Ws(mpsc::UnboundedSender<Message>),
+    /// An IRC client, fed raw protocol lines (without trailing CRLF).
+    Irc(mpsc::UnboundedSender<String>),
+}
+
+impl Sink {
+    /// Deliver a chat message from `from` in `room` to this client, rendered
+    /// in the client's own protocol.
+    fn send_chat(&self, from: &str, room: &str, text: &str) {
+        match self {
+            Sink::Ws(tx) => {
+                let _ = tx.send(Message::text(format!("<{}>: {}", from, text)));
+            }
+            Sink::Irc(tx) => {
+                let _ = tx.send(format!(":{} PRIVMSG #{} :{}", from, room, text));
+            }
+        }
+    }
+
+    /// Deliver a server notice (joins, parts, errors) to this client.
+    fn send_notice(&self, text: &str) {
+        match self {
+            Sink::Ws(tx) => {
+                let _ = tx.send(Message::text(text.to_string()));
+            }
+            Sink::Irc(tx) => {
+                let _ = tx.send(format!(":server NOTICE :{}", text));
+            }
+        }
+    }
+
+    /// Deliver a single previously-logged line verbatim, used for history
+    /// replay to a freshly joined client.
+    fn send_raw(&self, line: String) {
+        match self {
+            Sink::Ws(tx) => {
+                let _ = tx.send(Message::text(line));
+            }
+            Sink::Irc(tx) => {
+                let _ = tx.send(line);
+            }
+        }
+    }
+
+    /// Ask the client to close, if the transport has a notion of a close
+    /// frame. For IRC the connection is torn down by dropping the writer.
+    fn send_close(&self) {
+        if let Sink::Ws(tx) = self {
+            let _ = tx.send(Message::close());
+        }
+    }
+}
+
+/// A user that is connected to a room: their registered identity together
+/// with the outbound sink feeding their client.
+struct ConnectedUser {
+    user: RegisteredUser,
+    sink: Sink,
+}
+
 /// Our state of currently connected users.
 ///
 /// - Key is their id
-/// - Value is a sender of `warp::ws::Message`
-type Users = Arc<RwLock<HashMap<usize, mpsc::UnboundedSender<Message>>>>;
+/// - Value is the connected user, which pairs their registration with a
+///   protocol-agnostic `Sink`
+type Users = Arc<RwLock<HashMap<usize, ConnectedUser>>>;
 pub type Rooms = Arc<RwLock<HashMap<String, Users>>>;
 
-pub async fn join_room(ws: WebSocket, room_name: String, rooms: Rooms) {
-    //Acquire write guard to get map of users and then update
-    let mut room_guard = rooms.write().await;
-    let users = match room_guard.get(&room_name).clone() {
-        Some(users) => {
-            users.clone()
-        },
-        None => {
-            Users::default()
-        }
-    };
-    // Use a counter to assign a new unique ID for this user.
-    let my_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
+pub async fn join_room(mut ws: WebSocket, room_name: String, query: ChatQuery, rooms: Rooms, logger: Arc<Logger>, metrics: Arc<Metrics>, auth: Arc<AuthConfig>, mut shutdown: watch::Receiver<bool>) {
+    let history = query.history.unwrap_or(DEFAULT_HISTORY);
+
+    // If the room is private, run the authentication handshake before anything
+    // else. Unauthenticated sockets are closed here and never appear in room
+    // state or logs. Public rooms start already authenticated.
+    if let AuthState::Unauthenticated = authenticate(&mut ws, &room_name, &auth).await {
+        return;
+    }
 
+    // Before a client is added to the room they must complete a
+    // registration handshake: the next protocol frame (the first one, in a
+    // public room) has to be a JSON object carrying their identity. Sockets that fail the handshake never
+    // make it into the `Users` map.
+    let registration = match register(&mut ws).await {
+        Some(registration) => registration,
+        None => return,
+    };
 
     // Split the socket into a sender and receive of messages.
     let (mut user_ws_tx, mut user_ws_rx) = ws.split();
@@ -49,61 +164,286 @@ pub async fn join_room(ws: WebSocket, room_name: String, rooms: Rooms) {
         }
     });
 
-    // Save the sender in our list of connected users.
-    users.write().await.insert(my_id, tx);
-    room_guard.insert(room_name.clone(), users);
-    drop(room_guard);
-
-    // Return a `Future` that is basically a state machine managing
-    // this specific user's connection.
+    // Wrap the sender in a protocol-agnostic sink and run the shared join
+    // logic, which handles duplicate nicknames, history replay, and metrics.
+    let sink = Sink::Ws(tx);
+    let my_id = match register_user(&room_name, registration, sink.clone(), history, &rooms, &logger, &metrics).await {
+        Some(my_id) => my_id,
+        None => return,
+    };
 
-    // Every time the user sends a message, broadcast it to
-    // all other users...
-    while let Some(result) = user_ws_rx.next().await {
-        let msg = match result {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("websocket error(uid={}): {}", my_id, e);
+    // Every time the user sends a message, broadcast it to all other
+    // users. We also watch for a shutdown notification so the connection can
+    // be drained cleanly instead of being killed mid-write.
+    loop {
+        tokio::select! {
+            result = user_ws_rx.next() => {
+                let msg = match result {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        eprintln!("websocket error(uid={}): {}", my_id, e);
+                        break;
+                    }
+                    None => break,
+                };
+                // Skip any non-Text messages...
+                if let Ok(text) = msg.to_str() {
+                    user_message(my_id, text, &room_name, &rooms, &logger, &metrics).await;
+                }
+            }
+            _ = shutdown.changed() => {
+                // Server is going away: tell the client and tear down.
+                sink.send_close();
                 break;
             }
-        };
-        user_message(my_id, msg, &room_name, &rooms).await;
+        }
     }
 
     // user_ws_rx stream will keep processing as long as the user stays
     // connected. Once they disconnect, then...
-    user_disconnected(my_id, &room_name, &rooms).await;
+    user_disconnected(my_id, &room_name, &rooms, &metrics).await;
+}
+
+/// Shared room-join logic used by both the WebSocket and IRC front ends.
+///
+/// Rejects a duplicate nickname (via the sink, so the error is rendered in
+/// the caller's protocol) and returns `None`. Otherwise assigns a fresh id,
+/// replays up to `history` recent lines to the joining client only, inserts
+/// the user into room state, and updates the metrics. Returns the new id.
+pub(crate) async fn register_user(
+    room_name: &str,
+    user: RegisteredUser,
+    sink: Sink,
+    history: usize,
+    rooms: &Rooms,
+    logger: &Arc<Logger>,
+    metrics: &Arc<Metrics>,
+) -> Option<usize> {
+    //Acquire write guard to get map of users and then update
+    let mut room_guard = rooms.write().await;
+    let (users, new_room) = match room_guard.get(room_name).clone() {
+        Some(users) => (users.clone(), false),
+        None => (Users::default(), true),
+    };
+
+    // Nicknames must be unique within a room. Reject the duplicate and drop
+    // the connection without touching room state.
+    if users.read().await.values().any(|u| u.user.nickname == user.nickname) {
+        sink.send_notice(&format!("error: nickname '{}' is already in use in this room", user.nickname));
+        return None;
+    }
+
+    // Use a counter to assign a new unique ID for this user.
+    let my_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
+
+    // Replay the tail of the conversation to the joining user only, before
+    // they are wired into the broadcast loop, so they arrive in context.
+    if history > 0 {
+        for line in logger.get_history(room_name, history).await {
+            sink.send_raw(line);
+        }
+    }
+
+    // Save the sink in our list of connected users.
+    users.write().await.insert(my_id, ConnectedUser { user, sink });
+    room_guard.insert(room_name.to_string(), users);
+    drop(room_guard);
+
+    // Record the new connection in our metrics, and the room itself if this
+    // user opened it (the room was not in the map before this join).
+    metrics.connected_users.inc();
+    if new_room {
+        metrics.active_rooms.inc();
+    }
+
+    Some(my_id)
 }
 
-async fn user_message(my_id: usize, msg: Message, room_name: &str, rooms: &Rooms) {
-    // Skip any non-Text messages...
-    let msg = if let Ok(s) = msg.to_str() {
-        s
+/// Run the authentication handshake for a private room.
+///
+/// For a public room this is a no-op and returns `Authenticated`. For a
+/// private room it waits for an authenticate frame carrying a token; on a
+/// match it returns `Authenticated`, otherwise it sends an error frame and
+/// returns `Unauthenticated` so the caller drops the socket before any room
+/// state is touched.
+async fn authenticate(ws: &mut WebSocket, room_name: &str, auth: &AuthConfig) -> AuthState {
+    if !auth.requires_auth(room_name) {
+        return AuthState::Authenticated;
+    }
+
+    let msg = match ws.next().await {
+        Some(Ok(msg)) => msg,
+        _ => return AuthState::Unauthenticated,
+    };
+
+    let token = match msg.to_str().ok().and_then(|t| serde_json::from_str::<Authenticate>(t).ok()) {
+        Some(frame) => frame.token,
+        None => {
+            let _ = ws.send(Message::text("error: expected an authenticate frame")).await;
+            return AuthState::Unauthenticated;
+        }
+    };
+
+    if auth.verify(room_name, &token) {
+        AuthState::Authenticated
     } else {
-        return;
+        let _ = ws.send(Message::text("error: invalid authentication token")).await;
+        AuthState::Unauthenticated
+    }
+}
+
+/// Run the registration handshake on a freshly upgraded socket.
+///
+/// Waits for the next frame, which must be a JSON `RegisteredUser`. On a
+/// non-text frame or malformed JSON an error frame is sent back and `None` is
+/// returned so the caller drops the socket; a socket that closes or errors
+/// before sending a frame also yields `None`, with no error frame.
+async fn register(ws: &mut WebSocket) -> Option<RegisteredUser> {
+    let msg = match ws.next().await {
+        Some(Ok(msg)) => msg,
+        _ => return None,
+    };
+
+    let text = match msg.to_str() {
+        Ok(text) => text,
+        Err(_) => {
+            let _ = ws.send(Message::text("error: expected a registration frame")).await;
+            return None;
+        }
     };
 
-    let new_msg = format!("<User#{}>: {}", my_id, msg);
+    match serde_json::from_str::<RegisteredUser>(text) {
+        Ok(registration) => Some(registration),
+        Err(e) => {
+            let _ = ws.send(Message::text(format!("error: invalid registration frame: {}", e))).await;
+            None
+        }
+    }
+}
+
+pub(crate) async fn user_message(my_id: usize, msg: &str, room_name: &str, rooms: &Rooms, logger: &Arc<Logger>, metrics: &Arc<Metrics>) {
+    let nickname = if let Some(users) = rooms.read().await.get(room_name) {
+        let users = users.read().await;
+
+        // Look up the sender's nickname so the broadcast is readable.
+        let nickname = match users.get(&my_id) {
+            Some(user) => user.user.nickname.clone(),
+            None => return,
+        };
 
-    if let Some(users) = rooms.read().await.get(room_name) {
-        // New message from this user, send it to everyone else (except same uid)...
-        for (&uid, tx) in users.read().await.iter() {
+        // New message from this user, send it to everyone else (except same
+        // uid), each rendered in their own protocol...
+        for (&uid, user) in users.iter() {
             if my_id != uid {
-                if let Err(_disconnected) = tx.send(Message::text(new_msg.clone())) {
-                    // The tx is disconnected, our `user_disconnected` code
-                    // should be happening in another task, nothing more to
-                    // do here.
-                }
+                user.sink.send_chat(&nickname, room_name, msg);
             }
         }
+        metrics.messages_broadcast.inc();
+        nickname
+    } else {
+        return;
+    };
+
+    // Persist the broadcast to the room's log file as a structured record.
+    if let Some(writer) = logger.get_log_writer(room_name).await {
+        let record = LogRecord::new(room_name, my_id, &nickname, msg);
+        let _ = writer.log_message(record).await;
+    }
+}
+
+pub(crate) async fn user_disconnected(my_id: usize, room_name: &str, rooms: &Rooms, metrics: &Arc<Metrics>) {
+    // Hold the rooms write guard for the whole teardown so we can drop the
+    // room itself if this was the last user, without racing a new joiner.
+    let mut room_guard = rooms.write().await;
+    let users = match room_guard.get(room_name) {
+        Some(users) => users.clone(),
+        None => return,
+    };
+
+    let mut users_guard = users.write().await;
+    if let Some(user) = users_guard.remove(&my_id) {
+        eprintln!("good bye user: {} ({})", my_id, user.user.nickname);
+        metrics.connected_users.dec();
+
+        // Let the rest of the room know who left.
+        let notice = format!("* {} has left the room", user.user.nickname);
+        for other in users_guard.values() {
+            other.sink.send_notice(&notice);
+        }
+
+        // If the room is now empty, tear it down so `active_rooms` reflects
+        // rooms that actually have users rather than growing forever.
+        if users_guard.is_empty() {
+            drop(users_guard);
+            room_guard.remove(room_name);
+            metrics.active_rooms.dec();
+        }
     }
 }
 
-async fn user_disconnected(my_id: usize, room_name: &str, rooms: &Rooms) {
-    eprintln!("good bye user: {}", my_id);
+#[cfg(test)]
+mod test {
+    /// Testing module for the shared room-join logic and its metrics wiring.
+    use super::*;
+    use crate::logger::LogMode;
+
+    fn test_logger(metrics: &Arc<Metrics>) -> Arc<Logger> {
+        Arc::new(Logger::new(LogMode::Plaintext, watch::channel(false).1, metrics.clone()))
+    }
+
+    fn registered(nickname: &str) -> RegisteredUser {
+        RegisteredUser { nickname: nickname.to_string(), realname: nickname.to_string() }
+    }
+
+    #[tokio::test]
+    async fn register_user_counts_users_and_rooms() {
+        let rooms = Rooms::default();
+        let metrics = Arc::new(Metrics::new());
+        let logger = test_logger(&metrics);
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let id = register_user("lobby", registered("alice"), Sink::Irc(tx), 0, &rooms, &logger, &metrics).await;
+
+        assert!(id.is_some());
+        assert_eq!(1, metrics.connected_users.get());
+        assert_eq!(1, metrics.active_rooms.get());
+    }
+
+    #[tokio::test]
+    async fn register_user_rejects_duplicate_nickname() {
+        let rooms = Rooms::default();
+        let metrics = Arc::new(Metrics::new());
+        let logger = test_logger(&metrics);
+
+        let (tx1, _rx1) = mpsc::unbounded_channel();
+        register_user("lobby", registered("bob"), Sink::Irc(tx1), 0, &rooms, &logger, &metrics).await;
+
+        // A second user with the same nickname is rejected and notified.
+        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        let dup = register_user("lobby", registered("bob"), Sink::Irc(tx2), 0, &rooms, &logger, &metrics).await;
+
+        assert!(dup.is_none());
+        let notice = rx2.try_recv().expect("expected an error notice");
+        assert!(notice.contains("already in use"));
+        // The duplicate never joined, so only the first user is counted.
+        assert_eq!(1, metrics.connected_users.get());
+    }
+
+    #[tokio::test]
+    async fn disconnect_tears_down_empty_room() {
+        let rooms = Rooms::default();
+        let metrics = Arc::new(Metrics::new());
+        let logger = test_logger(&metrics);
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let id = register_user("lobby", registered("alice"), Sink::Irc(tx), 0, &rooms, &logger, &metrics)
+            .await
+            .unwrap();
+
+        user_disconnected(id, "lobby", &rooms, &metrics).await;
 
-    // Stream closed up, so remove from the user list
-    if let Some(users) = rooms.read().await.get(room_name) {
-        users.write().await.remove(&my_id);
+        assert_eq!(0, metrics.connected_users.get());
+        assert_eq!(0, metrics.active_rooms.get());
+        assert!(!rooms.read().await.contains_key("lobby"));
     }
 }
@@ -0,0 +1,228 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use futures_util::StreamExt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::auth::AuthConfig;
+use crate::handlers::chat_handlers::{register_user, user_disconnected, user_message, RegisteredUser, Rooms, Sink};
+use crate::logger::Logger;
+use crate::metrics::Metrics;
+
+/// Start the IRC gateway: a plaintext, line-based TCP front end speaking a
+/// minimal subset of IRC (NICK, USER, JOIN, PRIVMSG, PART, QUIT). It maps
+/// directly onto the same `Rooms` state as the WebSocket server, so IRC and
+/// WebSocket users in the same room see each other's messages and everything
+/// is logged through the shared `Logger`.
+pub async fn run(
+    addr: SocketAddr,
+    rooms: Rooms,
+    logger: Arc<Logger>,
+    metrics: Arc<Metrics>,
+    auth: Arc<AuthConfig>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("could not bind IRC listener on {}: {}", addr, e);
+            return;
+        }
+    };
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                match accepted {
+                    Ok((stream, _peer)) => {
+                        let rooms = rooms.clone();
+                        let logger = logger.clone();
+                        let metrics = metrics.clone();
+                        let auth = auth.clone();
+                        let shutdown = shutdown.clone();
+                        tokio::task::spawn(async move {
+                            handle_client(stream, rooms, logger, metrics, auth, shutdown).await;
+                        });
+                    }
+                    Err(e) => eprintln!("IRC accept error: {}", e),
+                }
+            }
+            _ = shutdown.changed() => break,
+        }
+    }
+}
+
+/// Drive a single IRC client connection through its lifetime: collect its
+/// identity, join it into a room, relay its messages through `user_message`,
+/// and clean up on PART/QUIT, disconnect, or server shutdown.
+async fn handle_client(
+    stream: tokio::net::TcpStream,
+    rooms: Rooms,
+    logger: Arc<Logger>,
+    metrics: Arc<Metrics>,
+    auth: Arc<AuthConfig>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = BufReader::new(read_half).lines();
+
+    // Spawn a writer task that renders outbound lines with CRLF terminators,
+    // fed through the same `Sink` abstraction the WebSocket path uses.
+    let (tx, rx) = mpsc::unbounded_channel::<String>();
+    let mut rx = UnboundedReceiverStream::new(rx);
+    tokio::task::spawn(async move {
+        while let Some(line) = rx.next().await {
+            if write_half.write_all(line.as_bytes()).await.is_err()
+                || write_half.write_all(b"\r\n").await.is_err()
+            {
+                break;
+            }
+        }
+    });
+    let sink = Sink::Irc(tx);
+
+    // Registration state, filled in by NICK/USER. A JOIN needs a NICK first;
+    // USER is optional and only sets the realname.
+    let mut nickname: Option<String> = None;
+    let mut realname = String::new();
+    // Token supplied via the IRC `PASS` command, checked against private rooms.
+    let mut pass: Option<String> = None;
+    // The room this client has joined, if any, and its assigned user id.
+    let mut joined: Option<(String, usize)> = None;
+
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => line,
+                // EOF or read error: fall through to disconnect handling.
+                _ => break,
+            },
+            _ = shutdown.changed() => {
+                sink.send_notice("server shutting down");
+                break;
+            }
+        };
+
+        let (command, args) = parse_line(&line);
+        match command.to_uppercase().as_str() {
+            "PASS" => {
+                pass = args.first().map(|s| s.to_string()).or_else(|| trailing(&line));
+            }
+            "NICK" => {
+                nickname = args.first().map(|s| s.to_string());
+            }
+            "USER" => {
+                // USER <user> <mode> <unused> :<realname>
+                realname = trailing(&line).unwrap_or_default();
+            }
+            "JOIN" => {
+                if joined.is_some() {
+                    sink.send_notice("error: already in a room");
+                    continue;
+                }
+                let nick = match &nickname {
+                    Some(nick) => nick.clone(),
+                    None => {
+                        sink.send_notice("error: set a nickname with NICK before joining");
+                        continue;
+                    }
+                };
+                let room = match args.first() {
+                    Some(channel) => channel.trim_start_matches('#').to_string(),
+                    None => {
+                        sink.send_notice("error: JOIN requires a channel");
+                        continue;
+                    }
+                };
+                // Private rooms require a matching token, supplied via PASS.
+                if auth.requires_auth(&room) && !auth.verify(&room, pass.as_deref().unwrap_or("")) {
+                    sink.send_notice("error: invalid authentication token");
+                    continue;
+                }
+                let user = RegisteredUser { nickname: nick, realname: realname.clone() };
+                match register_user(&room, user, sink.clone(), 0, &rooms, &logger, &metrics).await {
+                    Some(my_id) => joined = Some((room, my_id)),
+                    None => { /* duplicate nickname; error already sent */ }
+                }
+            }
+            "PRIVMSG" => {
+                if let Some((room, my_id)) = &joined {
+                    if let Some(text) = trailing(&line) {
+                        user_message(*my_id, &text, room, &rooms, &logger, &metrics).await;
+                    }
+                } else {
+                    sink.send_notice("error: JOIN a room before sending messages");
+                }
+            }
+            "PART" => {
+                if let Some((room, my_id)) = joined.take() {
+                    user_disconnected(my_id, &room, &rooms, &metrics).await;
+                }
+            }
+            "QUIT" => break,
+            _ => sink.send_notice(&format!("error: unknown command '{}'", command)),
+        }
+    }
+
+    // Connection is going away: clean up room state if still joined.
+    if let Some((room, my_id)) = joined {
+        user_disconnected(my_id, &room, &rooms, &metrics).await;
+    }
+}
+
+/// Split an IRC line into its command and space-separated arguments, stopping
+/// at a trailing `:`-prefixed parameter (which is returned by `trailing`).
+fn parse_line(line: &str) -> (&str, Vec<&str>) {
+    let head = match line.find(" :") {
+        Some(idx) => &line[..idx],
+        None => line,
+    };
+    let mut parts = head.split_whitespace();
+    let command = parts.next().unwrap_or("");
+    (command, parts.collect())
+}
+
+/// Return the trailing `:`-prefixed parameter of an IRC line, if present. This
+/// is the free-form text of a PRIVMSG or the realname of a USER command.
+fn trailing(line: &str) -> Option<String> {
+    line.find(" :").map(|idx| line[idx + 2..].to_string())
+}
+
+#[cfg(test)]
+mod test {
+    /// Testing module for the IRC line parsers.
+    use super::*;
+
+    #[test]
+    fn parse_line_splits_command_and_args() {
+        let (command, args) = parse_line("JOIN #rust");
+        assert_eq!("JOIN", command);
+        assert_eq!(vec!["#rust"], args);
+    }
+
+    #[test]
+    fn parse_line_stops_at_trailing_param() {
+        let (command, args) = parse_line("PRIVMSG #rust :hello world");
+        assert_eq!("PRIVMSG", command);
+        assert_eq!(vec!["#rust"], args);
+    }
+
+    #[test]
+    fn parse_line_handles_empty_input() {
+        let (command, args) = parse_line("");
+        assert_eq!("", command);
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn trailing_extracts_free_form_text() {
+        assert_eq!(Some("hello world".to_string()), trailing("PRIVMSG #rust :hello world"));
+    }
+
+    #[test]
+    fn trailing_absent_when_no_colon() {
+        assert_eq!(None, trailing("JOIN #rust"));
+    }
+}
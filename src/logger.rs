@@ -1,15 +1,78 @@
-use tokio::sync::{mpsc, RwLock};
-use std::collections::HashMap;
+use tokio::sync::{mpsc, watch, RwLock};
+use std::collections::{HashMap, VecDeque};
 use std::io::Result;
 use std::sync::{Arc};
-use futures_util::StreamExt;
+use futures_util::{FutureExt, StreamExt};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc::error::SendError;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use chrono::Utc;
+use serde::Serialize;
+use crate::metrics::Metrics;
+
+/// How log lines are rendered to disk. The replay buffer always holds the
+/// plaintext form, whatever the mode.
+#[derive(Debug, Clone, Copy)]
+pub enum LogMode {
+    /// Human-readable `<nickname>: message` lines, matching the original
+    /// behavior of the server.
+    Plaintext,
+    /// One serde_json object per line, for machine ingestion.
+    Json,
+}
+
+/// A single structured log entry. One of these is produced per broadcast and
+/// rendered according to the logger's `LogMode`. Serialized with serde_json
+/// it becomes one self-describing object per line.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogRecord {
+    /// When the message was logged, as an RFC3339 timestamp.
+    pub timestamp: String,
+    /// The room the message belongs to.
+    pub room: String,
+    /// The numeric id of the sender.
+    pub sender_id: usize,
+    /// The sender's registered nickname.
+    pub nickname: String,
+    /// The message text as typed by the user.
+    pub message: String,
+}
+
+impl LogRecord {
+    /// Build a record for a message, stamping it with the current time.
+    pub fn new(room: &str, sender_id: usize, nickname: &str, message: &str) -> LogRecord {
+        LogRecord {
+            timestamp: Utc::now().to_rfc3339(),
+            room: room.to_string(),
+            sender_id,
+            nickname: nickname.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Render the record to a single log line (without a trailing newline)
+    /// in the requested mode.
+    fn render(&self, mode: LogMode) -> String {
+        match mode {
+            LogMode::Plaintext => format!("<{}>: {}", self.nickname, self.message),
+            LogMode::Json => serde_json::to_string(self).unwrap(),
+        }
+    }
+}
 
 type LogMap = Arc<RwLock<HashMap<String, LogWriterHandle>>>;
 
+/// Per-room ring buffer of the most recently logged lines. A new user can be
+/// replayed the tail of the conversation straight from memory without
+/// re-reading the whole log file off disk.
+type History = Arc<RwLock<HashMap<String, VecDeque<String>>>>;
+
+/// The largest number of recent lines we retain per room for replay.
+const MAX_HISTORY: usize = 100;
+
 /// The Logger is a wrapper around a HashMap. This allows us to pass a reference to a Logger
 /// as part of our application state through a warp filter. The Logger keeps a map of rooms to a
 /// LogWriterHandle. The LogWriterHandle will be cloned and return to clients so that many clients
@@ -19,13 +82,48 @@ type LogMap = Arc<RwLock<HashMap<String, LogWriterHandle>>>;
 /// a LogWriterHandle
 pub struct Logger {
     log_map: LogMap,
+    history: History,
+    mode: LogMode,
+    shutdown: watch::Receiver<bool>,
+    writers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    metrics: Arc<Metrics>,
 }
 
 impl Logger {
-    /// Create a new Logger. The default state is an empty map.
-    pub fn new() -> Logger {
+    /// Create a new Logger. The default state is an empty map. The `LogMode`
+    /// selects how records are rendered (plaintext vs JSON). The `shutdown`
+    /// receiver lets each `LogWriter` flush and drop its file on termination
+    /// so no queued messages are lost. The shared `Metrics` is handed to
+    /// every `LogWriter` so bytes written to disk are counted.
+    pub fn new(mode: LogMode, shutdown: watch::Receiver<bool>, metrics: Arc<Metrics>) -> Logger {
         Logger {
             log_map: LogMap::default(),
+            history: History::default(),
+            mode,
+            shutdown,
+            writers: Arc::new(Mutex::new(Vec::new())),
+            metrics,
+        }
+    }
+
+    /// Await every spawned `LogWriter` task so their drain-and-flush on
+    /// shutdown actually completes before the runtime is torn down. Call this
+    /// after signaling shutdown; the writers observe the signal, flush their
+    /// files, and exit.
+    pub async fn join_writers(&self) {
+        let handles: Vec<JoinHandle<()>> = self.writers.lock().await.drain(..).collect();
+        for handle in handles {
+            let _ = handle.await;
+        }
+    }
+
+    /// Return up to the last `n` logged lines for a room, oldest first, so a
+    /// user joining mid-conversation can be caught up. Reads from the
+    /// in-memory ring buffer, so this is O(n) rather than O(file size).
+    pub async fn get_history(&self, room_name: &str, n: usize) -> Vec<String> {
+        match self.history.read().await.get(room_name) {
+            Some(buffer) => buffer.iter().rev().take(n).rev().cloned().collect(),
+            None => Vec::new(),
         }
     }
     /// Returns a LogWriterHandle the client can use to send log messages
@@ -38,8 +136,10 @@ impl Logger {
             }
             None => {
                 // If it does not exist create one and put it in map. Return a clone
-                match LogWriterHandle::new(room_name).await {
-                    Some(log_writer) => {
+                match LogWriterHandle::new(room_name, self.history.clone(), self.mode, self.shutdown.clone(), self.metrics.clone()).await {
+                    Some((log_writer, handle)) => {
+                        // Track the writer task so it can be joined on shutdown.
+                        self.writers.lock().await.push(handle);
                         let writer = log_writer.clone();
                         write_lock.insert(room_name.to_string(), log_writer);
                         Some(writer)
@@ -60,20 +160,22 @@ impl Logger {
 /// which loops forever waiting for messages.
 #[derive(Clone)]
 pub struct LogWriterHandle {
-    tx: mpsc::UnboundedSender<String>,
+    tx: mpsc::UnboundedSender<LogRecord>,
 }
 
 impl LogWriterHandle {
     /// Create a new LogWriterHandle. This also creates the mpsc channel to interact with the
     /// LogWriter. A new LogWriter is created then a new tokio task is spawned which runs the
-    /// LogWriter's run method, an infinite loop to write messages to a file.
-    async fn new(room_name: &str) -> Option<Self> {
+    /// LogWriter's run method, a loop that writes messages to a file until shutdown or until
+    /// every handle is dropped. The task's
+    /// `JoinHandle` is returned alongside the handle so it can be awaited on shutdown.
+    async fn new(room_name: &str, history: History, mode: LogMode, shutdown: watch::Receiver<bool>, metrics: Arc<Metrics>) -> Option<(Self, JoinHandle<()>)> {
         let (tx, rx) = mpsc::unbounded_channel();
         let rx = UnboundedReceiverStream::new(rx);
-        match LogWriter::new(room_name, rx).await {
+        match LogWriter::new(room_name, rx, history, mode, shutdown, metrics).await {
             Ok(mut log_writer) => {
-                tokio::task::spawn(async move { log_writer.run().await; });
-                Some(Self { tx })
+                let handle = tokio::task::spawn(async move { log_writer.run().await; });
+                Some((Self { tx }, handle))
             }
             Err(e) => {
                 eprintln!("Error creating LogWriter {}", e);
@@ -82,16 +184,22 @@ impl LogWriterHandle {
         }
     }
 
-    /// Public method for clients to interact with our LogWriterHandle. Clients send a message
-    /// which this function takes ownership of and will send to the LogWriter to be logged.
-    pub async fn log_message(&self, message: String) -> std::result::Result<(), SendError<String>> {
-        self.tx.send(message)
+    /// Public method for clients to interact with our LogWriterHandle. Clients send a structured
+    /// `LogRecord` which this function takes ownership of and will send to the LogWriter to be
+    /// rendered and logged.
+    pub async fn log_message(&self, record: LogRecord) -> std::result::Result<(), SendError<LogRecord>> {
+        self.tx.send(record)
     }
 }
 
 struct LogWriter {
-    rx: UnboundedReceiverStream<String>,
+    room_name: String,
+    rx: UnboundedReceiverStream<LogRecord>,
     file: File,
+    history: History,
+    mode: LogMode,
+    shutdown: watch::Receiver<bool>,
+    metrics: Arc<Metrics>,
 }
 
 /// A LogWriter represents the writer to a single log file. The Writer has a receiver end
@@ -103,7 +211,7 @@ impl LogWriter {
     /// args:
     /// room_name: The chat room name which will act as the log file name
     /// rx: the receiver end of the create mpsc channel.
-    pub async fn new(room_name: &str, rx: UnboundedReceiverStream<String>) -> Result<Self> {
+    pub async fn new(room_name: &str, rx: UnboundedReceiverStream<LogRecord>, history: History, mode: LogMode, shutdown: watch::Receiver<bool>, metrics: Arc<Metrics>) -> Result<Self> {
         let path = format!("logs/{}.log", room_name);
         let file = OpenOptions::new()
             .append(true)
@@ -113,8 +221,13 @@ impl LogWriter {
         match file {
             Ok(file) => {
                 Ok(LogWriter {
+                    room_name: room_name.to_string(),
                     rx,
                     file,
+                    history,
+                    mode,
+                    shutdown,
+                    metrics,
                 })
             }
             Err(e) => {
@@ -123,22 +236,62 @@ impl LogWriter {
         }
     }
 
-    /// Log a message to a file.
+    /// Log a record to a file.
     /// args:
-    /// message: The formatted message to log to the File referenced in self.file
-    async fn log_message(&mut self, message: String) {
-        match self.file.write_all(message.as_bytes()).await {
-            Ok(_) => { /* Nothing to do here */ }
+    /// record: The structured record to render and log to the File referenced in self.file
+    async fn log_message(&mut self, record: LogRecord) {
+        let line = format!("{}\n", record.render(self.mode));
+        match self.file.write_all(line.as_bytes()).await {
+            Ok(_) => {
+                self.metrics.bytes_logged.inc_by(line.len() as u64);
+                // The ring buffer is replayed to joining clients, so always
+                // store the readable chat form regardless of the on-disk mode;
+                // otherwise a JSON-mode server would replay raw JSON blobs.
+                self.push_history(&record.render(LogMode::Plaintext)).await;
+            }
             Err(e) => {
-                eprintln!("Could not log message {} with error {}", message, e);
+                eprintln!("Could not log message {} with error {}", line, e);
             }
         }
     }
 
-    /// Run the LogWriter. This sits in a loop waiting to receive messages to log
+    /// Push a freshly written line onto this room's ring buffer, evicting the
+    /// oldest entry once `MAX_HISTORY` is exceeded so replay stays bounded.
+    async fn push_history(&self, message: &str) {
+        let mut history = self.history.write().await;
+        let buffer = history.entry(self.room_name.clone()).or_default();
+        buffer.push_back(message.trim_end().to_string());
+        while buffer.len() > MAX_HISTORY {
+            buffer.pop_front();
+        }
+    }
+
+    /// Run the LogWriter. This sits in a loop waiting to receive messages to
+    /// log, and also watches for a shutdown signal. On shutdown it drains any
+    /// records already queued on the channel, then flushes and drops its file
+    /// so nothing in flight is truncated.
     async fn run(&mut self) {
-        while let Some(message) = self.rx.next().await {
-            self.log_message(message).await;
+        loop {
+            tokio::select! {
+                record = self.rx.next() => {
+                    match record {
+                        Some(record) => self.log_message(record).await,
+                        None => break,
+                    }
+                }
+                _ = self.shutdown.changed() => {
+                    // Drain whatever is already buffered without blocking on
+                    // senders that may still be alive, then stop.
+                    while let Some(Some(record)) = self.rx.next().now_or_never() {
+                        self.log_message(record).await;
+                    }
+                    break;
+                }
+            }
+        }
+
+        if let Err(e) = self.file.flush().await {
+            eprintln!("Could not flush log for room {}: {}", self.room_name, e);
         }
     }
 }
@@ -151,13 +304,13 @@ mod test {
 
     #[tokio::test]
     async fn test_default_logger() {
-        let logger = Logger::new();
+        let logger = Logger::new(LogMode::Plaintext, watch::channel(false).1, Arc::new(Metrics::new()));
         assert_eq!(0, logger.log_map.read().await.len());
     }
 
     #[tokio::test]
     async fn test_logger_one_room() {
-        let logger = Logger::new();
+        let logger = Logger::new(LogMode::Plaintext, watch::channel(false).1, Arc::new(Metrics::new()));
         match logger.get_log_writer("test").await {
             Some(_) => {
                 assert_eq!(1, logger.log_map.read().await.len());
@@ -170,10 +323,10 @@ mod test {
 
     #[tokio::test]
     async fn test_log_writer_handle_log_message() {
-        let logger = Logger::new();
+        let logger = Logger::new(LogMode::Plaintext, watch::channel(false).1, Arc::new(Metrics::new()));
         match logger.get_log_writer("test").await {
             Some(writer) => {
-                match writer.log_message(String::from("test message\n")).await {
+                match writer.log_message(LogRecord::new("test", 1, "tester", "test message")).await {
                     Ok(_) => {
                         let path = "logs/test.log";
                         match tokio::fs::File::open(path).await {
@@ -181,7 +334,7 @@ mod test {
                                 let mut buf_reader = BufReader::new(file);
                                 let mut log_line = String::new();
                                 let _num_bytes = buf_reader.read_line(&mut log_line).await;
-                                assert_eq!(String::from("test message\n"), log_line);
+                                assert_eq!(String::from("<tester>: test message\n"), log_line);
                             }
                             Err(e) => {
                                 assert!(false, "{}", format!("Could not open the file at path {} error {}", path, e));
@@ -1,14 +1,25 @@
 // #![deny(warnings)]
 use warp::Filter;
 use std::sync::Arc;
+use tokio::sync::watch;
 
 mod handlers;
 
-use handlers::chat_handlers::Rooms;
+use handlers::chat_handlers::{ChatQuery, Rooms};
 
 mod logger;
 
-use logger::Logger;
+use logger::{Logger, LogMode};
+
+mod metrics;
+
+use metrics::Metrics;
+
+mod irc;
+
+mod auth;
+
+use auth::AuthConfig;
 
 #[tokio::main]
 async fn main() {
@@ -18,29 +29,106 @@ async fn main() {
     // is a Users Map
     let rooms = Rooms::default();
 
+    //Collect runtime metrics, shared the same way as the rooms and logger
+    let metrics = Arc::new(Metrics::new());
+
+    // Shutdown signal broadcast to every connection loop and log writer so
+    // they can drain and clean up when the process is asked to terminate.
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
     //Create a logger
-    let logger = Arc::new(Logger::new());
+    let logger = Arc::new(Logger::new(LogMode::Json, shutdown_rx.clone(), metrics.clone()));
+    // Keep a handle so we can join the log writer tasks on shutdown.
+    let logger_shutdown = logger.clone();
+
+    //Load the optional per-room authentication config (empty => all public)
+    let auth = Arc::new(AuthConfig::load());
+
+    // Bring up the IRC gateway alongside the WebSocket server so standard IRC
+    // clients can join the same rooms.
+    tokio::task::spawn(irc::run(
+        ([127, 0, 0, 1], 6667).into(),
+        rooms.clone(),
+        logger.clone(),
+        metrics.clone(),
+        auth.clone(),
+        shutdown_rx.clone(),
+    ));
 
     let rooms = warp::any().map(move || rooms.clone());
     let logger = warp::any().map(move || logger.clone());
+    let metrics = warp::any().map(move || metrics.clone());
+    let auth = warp::any().map(move || auth.clone());
+    let shutdown = warp::any().map(move || shutdown_rx.clone());
 
     // GET /chat/<roomId> -> websocket upgrade
     let chat = warp::path!("chat" / String)
         // The `ws()` filter will prepare Websocket handshake...
         .and(warp::ws())
+        .and(warp::query::<ChatQuery>())
         .and(rooms)
         .and(logger)
-        .map(|room_name: String, ws: warp::ws::Ws, rooms: Rooms, logger: Arc<Logger>| {
+        .and(metrics.clone())
+        .and(auth)
+        .and(shutdown)
+        .map(|room_name: String, ws: warp::ws::Ws, query: ChatQuery, rooms: Rooms, logger: Arc<Logger>, metrics: Arc<Metrics>, auth: Arc<AuthConfig>, shutdown: watch::Receiver<bool>| {
             // This will call our function if the handshake succeeds.
-            ws.on_upgrade(move |socket| handlers::chat_handlers::join_room(socket, room_name, rooms, logger.clone()))
+            ws.on_upgrade(move |socket| handlers::chat_handlers::join_room(socket, room_name, query, rooms, logger.clone(), metrics.clone(), auth.clone(), shutdown.clone()))
         });
 
+    // GET /metrics -> prometheus text exposition
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(metrics)
+        .map(|metrics: Arc<Metrics>| metrics.render());
+
     // GET / -> index html
     let index = warp::path::end().map(|| {
         warp::reply::html(std::fs::read_to_string("src/www/index.html").unwrap())
     });
 
-    let routes = index.or(chat);
+    let routes = index.or(chat).or(metrics_route);
+
+    // Wait for a termination signal, then notify every connection loop and
+    // log writer before warp stops accepting and drains in-flight work.
+    let graceful = async move {
+        shutdown_signal().await;
+        eprintln!("shutdown signal received, draining connections");
+        let _ = shutdown_tx.send(true);
+    };
+
+    let (_addr, server) =
+        warp::serve(routes).bind_with_graceful_shutdown(([127, 0, 0, 1], 3030), graceful);
+    server.await;
+
+    // The server has stopped and connections have drained; now wait for the
+    // log writers to flush and exit so no queued messages are lost.
+    logger_shutdown.join_writers().await;
+}
+
+/// Resolve when the process receives SIGINT (Ctrl-C) or SIGTERM, whichever
+/// comes first, so the server can shut down gracefully either under an
+/// interactive Ctrl-C or a supervisor's termination request.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
\ No newline at end of file
@@ -0,0 +1,58 @@
+use prometheus::{IntCounter, IntGauge, Registry};
+
+/// The Metrics struct bundles the prometheus `Registry` together with the
+/// individual collectors the chat server updates at runtime. A single
+/// `Arc<Metrics>` is threaded through the application state the same way the
+/// `Rooms` map and the `Logger` are, so every handler can record activity
+/// without reaching for global state. The `Registry` is scraped by the
+/// `GET /metrics` route and rendered in the text exposition format.
+pub struct Metrics {
+    registry: Registry,
+    /// Number of users currently connected across all rooms.
+    pub connected_users: IntGauge,
+    /// Number of rooms that currently have at least one connected user.
+    pub active_rooms: IntGauge,
+    /// Total chat messages broadcast to other users.
+    pub messages_broadcast: IntCounter,
+    /// Total bytes successfully written to the room log files.
+    pub bytes_logged: IntCounter,
+}
+
+impl Metrics {
+    /// Create a new Metrics, building each collector and registering it with
+    /// a fresh `Registry`.
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        let connected_users =
+            IntGauge::new("chat_connected_users", "Currently connected users").unwrap();
+        let active_rooms =
+            IntGauge::new("chat_active_rooms", "Currently active rooms").unwrap();
+        let messages_broadcast =
+            IntCounter::new("chat_messages_broadcast_total", "Total messages broadcast").unwrap();
+        let bytes_logged =
+            IntCounter::new("chat_bytes_logged_total", "Total bytes written to logs").unwrap();
+
+        registry.register(Box::new(connected_users.clone())).unwrap();
+        registry.register(Box::new(active_rooms.clone())).unwrap();
+        registry.register(Box::new(messages_broadcast.clone())).unwrap();
+        registry.register(Box::new(bytes_logged.clone())).unwrap();
+
+        Metrics {
+            registry,
+            connected_users,
+            active_rooms,
+            messages_broadcast,
+            bytes_logged,
+        }
+    }
+
+    /// Gather every registered collector and render it in prometheus' text
+    /// exposition format, ready to be returned from the `/metrics` route.
+    pub fn render(&self) -> String {
+        use prometheus::Encoder;
+        let encoder = prometheus::TextEncoder::new();
+        let mut buffer = Vec::new();
+        encoder.encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}